@@ -1,16 +1,20 @@
 use std::{
     hash::{DefaultHasher, Hash, Hasher},
     str::FromStr,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use axum::{
-    body::Body,
+    body::{to_bytes, Body},
     extract::{Request, State},
     handler::Handler,
     http::{
         uri::{Authority, Scheme},
-        StatusCode, Uri,
+        HeaderMap, Method, StatusCode, Uri, Version,
     },
     response::IntoResponse,
 };
@@ -18,39 +22,137 @@ use hyper_util::{
     client::legacy::{connect::HttpConnector, Client},
     rt::TokioExecutor,
 };
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 
-struct RoundRobin {
+/// Maximum number of backends `proxy` will try before giving up on a request.
+const MAX_ATTEMPTS: usize = 3;
+/// How often the background task probes each backend's liveness.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A fixed backend list plus per-backend liveness flags, shared between the
+/// load balancers and the background health checker.
+#[derive(Clone)]
+struct Backends {
     addrs: Vec<&'static str>,
-    req_counter: Arc<AtomicUsize>,
+    healthy: Arc<Vec<AtomicBool>>,
+}
+
+impl Backends {
+    fn new(addrs: Vec<&'static str>) -> Self {
+        let healthy = Arc::new(addrs.iter().map(|_| AtomicBool::new(true)).collect());
+        Self { addrs, healthy }
+    }
+
+    fn mark(&self, index: usize, up: bool) {
+        self.healthy[index].store(up, Ordering::Relaxed);
+    }
+
+    fn is_healthy(&self, index: usize) -> bool {
+        self.healthy[index].load(Ordering::Relaxed)
+    }
+
+    /// Backend indexes in `order`, skipping down ones. Falls back to the full
+    /// (unfiltered) order if every backend looks down, so a flaky health
+    /// check can never take the whole proxy offline.
+    fn healthy_order(&self, order: impl Iterator<Item = usize>) -> Vec<String> {
+        let order: Vec<usize> = order.collect();
+        let healthy: Vec<String> = order
+            .iter()
+            .copied()
+            .filter(|&i| self.is_healthy(i))
+            .map(|i| self.addrs[i].to_string())
+            .collect();
+
+        if healthy.is_empty() {
+            order.into_iter().map(|i| self.addrs[i].to_string()).collect()
+        } else {
+            healthy
+        }
+    }
+
+    async fn check(&self) {
+        for (index, addr) in self.addrs.iter().enumerate() {
+            let up = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, TcpStream::connect(addr))
+                .await
+                .map(|res| res.is_ok())
+                .unwrap_or(false);
+            self.mark(index, up);
+        }
+    }
+
+    fn spawn_health_checker(self) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.check().await;
+            }
+        });
+    }
 }
 
 trait LoadBalancer {
     fn next_server(&self, req: &Request) -> String;
+
+    /// Ordered backends to try for `req`, most preferred first. Used by
+    /// `proxy` to fail over when the preferred backend is down or erroring.
+    /// Defaults to a single-element list built from `next_server`.
+    fn candidates(&self, req: &Request) -> Vec<String> {
+        vec![self.next_server(req)]
+    }
+}
+
+struct RoundRobin {
+    backends: Backends,
+    req_counter: Arc<AtomicUsize>,
 }
 
+impl LoadBalancer for RoundRobin {
+    fn next_server(&self, _req: &Request) -> String {
+        self.candidates(_req).remove(0)
+    }
+
+    fn candidates(&self, _req: &Request) -> Vec<String> {
+        let count = self.req_counter.fetch_add(1, Ordering::Relaxed);
+        let len = self.backends.addrs.len();
+        self.backends
+            .healthy_order((0..len).map(|offset| (count + offset) % len))
+    }
+}
+
+/// Routes by the numeric `:id` segment in paths like `/clients/:id/transaction`
+/// so every request for the same client lands on the same backend, even
+/// though each API node only holds accounts in its own in-memory `HashMap`.
 struct RinhaAccountBalance {
-    addrs: Vec<&'static str>,
+    backends: Backends,
+}
+
+/// Pulls the client id out of `/clients/<id>/...`, hashing only that segment
+/// instead of the full path so sibling routes for the same client agree.
+fn client_id(path: &str) -> &str {
+    path.split('/')
+        .skip_while(|segment| *segment != "clients")
+        .nth(1)
+        .unwrap_or(path)
 }
 
 impl LoadBalancer for RinhaAccountBalance {
     fn next_server(&self, req: &Request) -> String {
-        let path = req.uri().path();
+        self.candidates(req).remove(0)
+    }
+
+    fn candidates(&self, req: &Request) -> Vec<String> {
+        let id = client_id(req.uri().path());
         let hash = {
             let mut hasher = DefaultHasher::new();
-            path.hash(&mut hasher);
+            id.hash(&mut hasher);
             hasher.finish() as usize
         };
-        self.addrs[hash % self.addrs.len()].to_string()
-    }
-}
-
-impl LoadBalancer for RoundRobin {
-    fn next_server(&self, _req: &Request) -> String {
-        let count = self
-            .req_counter
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        self.addrs[count % self.addrs.len()].to_string()
+        let len = self.backends.addrs.len();
+        let start = hash % len;
+        self.backends
+            .healthy_order((0..len).map(|offset| (start + offset) % len))
     }
 }
 
@@ -64,43 +166,71 @@ struct AppState {
 async fn main() {
     let litenner = TcpListener::bind("0.0.0.0:9999").await.unwrap();
     let addrs = vec!["api01:9998", "api02:9997"];
+    let backends = Backends::new(addrs);
+    backends.clone().spawn_health_checker();
+
     let http_client = Client::builder(TokioExecutor::new())
         .http2_only(true)
         .build_http::<Body>();
     let req_counter = Arc::new(AtomicUsize::new(0));
-    let round_robin = RoundRobin {
-        addrs: addrs.clone(),
-        req_counter: req_counter.clone(),
-    };
-    let _fixed_load_balance = RinhaAccountBalance {
-        addrs: addrs.clone(),
+    let _round_robin = RoundRobin {
+        backends: backends.clone(),
+        req_counter,
     };
+    let client_routed = RinhaAccountBalance { backends };
     let app_state = AppState {
         http_client,
-        load_balance: Arc::new(round_robin),
+        load_balance: Arc::new(client_routed),
     };
     let app = proxy.with_state(app_state);
     axum::serve(litenner, app).await.unwrap();
 }
 
+fn retarget(uri: &Uri, addr: &str) -> Uri {
+    let mut parts = uri.clone().into_parts();
+    parts.authority = Authority::from_str(addr).ok();
+    parts.scheme = Some(Scheme::HTTP);
+    Uri::from_parts(parts).unwrap()
+}
+
+fn rebuild_request(
+    method: &Method,
+    uri: Uri,
+    version: Version,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Request {
+    let mut builder = Request::builder().method(method).uri(uri).version(version);
+    *builder.headers_mut().unwrap() = headers.clone();
+    builder.body(Body::from(body.to_vec())).unwrap()
+}
+
 async fn proxy(
     State(AppState {
         http_client,
         load_balance,
     }): State<AppState>,
-    mut req: Request,
+    req: Request,
 ) -> impl IntoResponse {
-    let addr = load_balance.next_server(&req);
-    *req.uri_mut() = {
-        let uri = req.uri();
-        let mut parts = uri.clone().into_parts();
-        parts.authority = Authority::from_str(&addr.as_str()).ok();
-        parts.scheme = Some(Scheme::HTTP);
-        Uri::from_parts(parts).unwrap()
+    let candidates = load_balance.candidates(&req);
+
+    let (parts, body) = req.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(_) => return Err(StatusCode::BAD_GATEWAY),
     };
 
-    match http_client.request(req).await {
-        Ok(res) => Ok(res),
-        Err(_) => Err(StatusCode::BAD_GATEWAY),
+    let mut last_error = StatusCode::BAD_GATEWAY;
+    for addr in candidates.into_iter().take(MAX_ATTEMPTS) {
+        let uri = retarget(&parts.uri, &addr);
+        let req = rebuild_request(&parts.method, uri, parts.version, &parts.headers, &body);
+
+        match http_client.request(req).await {
+            Ok(res) if !res.status().is_server_error() => return Ok(res),
+            Ok(res) => last_error = res.status(),
+            Err(_) => last_error = StatusCode::BAD_GATEWAY,
+        }
     }
+
+    Err(last_error)
 }
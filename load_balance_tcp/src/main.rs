@@ -3,6 +3,9 @@ use tokio::{
     net::{TcpListener, TcpStream},
 };
 
+/// Backends to try, in order, before giving up on a connection.
+const MAX_ATTEMPTS: usize = 2;
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let listener = TcpListener::bind("0.0.0.0:9999").await?;
@@ -10,12 +13,26 @@ async fn main() -> std::io::Result<()> {
     let mut count = 1;
     while let Ok((mut downstream, _)) = listener.accept().await {
         count += 1;
-        let addr = addrs[count % addrs.len()];
+        let start = count;
+        let addrs = addrs;
         tokio::spawn(async move {
-            let mut upstream = TcpStream::connect(addr).await.unwrap();
-            io::copy_bidirectional(&mut downstream, &mut upstream)
-                .await
-                .unwrap();
+            let mut upstream = None;
+            for attempt in 0..MAX_ATTEMPTS.min(addrs.len()) {
+                let addr = addrs[(start + attempt) % addrs.len()];
+                match TcpStream::connect(addr).await {
+                    Ok(stream) => {
+                        upstream = Some(stream);
+                        break;
+                    }
+                    Err(_) => continue,
+                }
+            }
+
+            let Some(mut upstream) = upstream else {
+                return;
+            };
+
+            let _ = io::copy_bidirectional(&mut downstream, &mut upstream).await;
         });
     }
 
@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
     routing::{get, post},
@@ -12,10 +12,14 @@ use std::{
     collections::{HashMap, VecDeque},
     env,
     sync::Arc,
+    time::Duration,
 };
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tokio::sync::RwLock;
 
+/// How often each account's ledger is checked against the compaction policy.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(try_from = "String")]
 struct Description(String);
@@ -79,7 +83,7 @@ impl Account {
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let mut db = Db::<(i64, Transaction), 128>::from_path(path)?;
 
-        let mut transaction = db.rows().collect::<Vec<_>>();
+        let mut transaction = db.rows().collect::<Result<Vec<_>, _>>()?;
 
         let balance = transaction
             .last()
@@ -96,24 +100,61 @@ impl Account {
         })
     }
 
-    pub fn transact(&mut self, transaction: Transaction) -> Result<(), &'static str> {
+    pub fn transact(&mut self, transaction: Transaction) -> Result<(), TransactError> {
         let balance = match transaction.kind {
             TransactionType::Credit => self.balance + transaction.value,
             TransactionType::Debit => {
                 if self.balance + self.limit >= transaction.value {
                     self.balance - transaction.value
                 } else {
-                    return Err("Not enough balance");
+                    return Err(TransactError::InsufficientBalance);
                 }
             }
         };
         self.db
             .insert((balance, transaction.clone()))
-            .map_err(|_| "Failed to persist data")?;
+            .map_err(|_| TransactError::Persist)?;
         self.balance = balance;
         self.transaction.push(transaction);
         Ok(())
     }
+
+    /// Full persisted history (not just the last-10 `RingBuffer` window), paged
+    /// off disk through the `Db`'s LRU page cache.
+    pub fn history(
+        &mut self,
+        page: usize,
+        limit: usize,
+    ) -> Result<Vec<(i64, Transaction)>, &'static str> {
+        let offset = page.saturating_sub(1) * limit;
+        self.db
+            .row_range(offset, limit)
+            .map_err(|_| "Failed to read history")
+    }
+
+    /// Reclaims checksum-garbage rows from the ledger's sealed segments if
+    /// the policy hook says it's worth it. Called periodically from a
+    /// background task. Does not evict superseded rows — the ledger's full
+    /// history must stay servable, so under normal (non-corrupt) operation
+    /// this is a no-op; see `db::Db::compact`.
+    pub fn maybe_compact(&mut self) -> Result<bool, &'static str> {
+        let should = self
+            .db
+            .should_compact()
+            .map_err(|_| "Failed to evaluate compaction policy")?;
+        if should {
+            self.db.compact().map_err(|_| "Failed to compact ledger")?;
+        }
+        Ok(should)
+    }
+}
+
+/// Distinguishes a rejected transaction (the client's fault, 422) from a
+/// failure to durably persist one (ours, 5xx) so `create_transaction` can
+/// pick the right status code instead of collapsing both into the same one.
+enum TransactError {
+    InsufficientBalance,
+    Persist,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -141,6 +182,12 @@ struct TransactionPay {
     description: String,
 }
 
+#[derive(Deserialize)]
+struct ExtractQuery {
+    page: Option<usize>,
+    limit: Option<usize>,
+}
+
 type AppState = Arc<HashMap<u8, RwLock<Account>>>;
 
 #[tokio::main]
@@ -173,10 +220,26 @@ async fn main() {
         ),
     ]);
 
+    let account_ids: Vec<u8> = accounts.keys().copied().collect();
+    let accounts = Arc::new(accounts);
+
+    for id in account_ids {
+        let accounts = accounts.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(COMPACTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(account) = accounts.get(&id) {
+                    let _ = account.write().await.maybe_compact();
+                }
+            }
+        });
+    }
+
     let app = Router::new()
         .route("/clients/:id/transaction", post(create_transaction))
         .route("/clients/:id/extract", get(view_account))
-        .with_state(Arc::new(accounts));
+        .with_state(accounts);
 
     let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
         .await
@@ -198,7 +261,8 @@ async fn create_transaction(
                     "limit": account.limit,
                     "balance": account.balance
                 }))),
-                Err(_) => Err(StatusCode::UNPROCESSABLE_ENTITY),
+                Err(TransactError::InsufficientBalance) => Err(StatusCode::UNPROCESSABLE_ENTITY),
+                Err(TransactError::Persist) => Err(StatusCode::INTERNAL_SERVER_ERROR),
             }
         }
         None => Err(StatusCode::NOT_FOUND),
@@ -208,9 +272,28 @@ async fn create_transaction(
 async fn view_account(
     Path(account_id): Path<u8>,
     State(accounts): State<AppState>,
+    Query(query): Query<ExtractQuery>,
 ) -> impl IntoResponse {
     match accounts.get(&account_id) {
         Some(account) => {
+            if let Some(page) = query.page {
+                let limit = query.limit.unwrap_or(10);
+                let mut account = account.write().await;
+                return match account.history(page, limit) {
+                    Ok(history) => Ok(Json(json!({
+                        "saldo": {
+                            "total": account.balance,
+                            "data_extrato": OffsetDateTime::now_utc().format(&Rfc3339).unwrap(),
+                            "limite": account.limit
+                        },
+                        "pagina": page,
+                        "limite": limit,
+                        "transacoes": history,
+                    }))),
+                    Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+                };
+            }
+
             let account = account.read().await;
             Ok(Json(json!({
                 "saldo": {